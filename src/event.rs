@@ -31,6 +31,25 @@ pub struct ContactInfoResult {
     pub result: io::Result<OurContactInfo>,
 }
 
+/// How an advertised external address was obtained.  A peer uses this to prioritise dialing
+/// addresses that are backed by a confirmed port mapping over ones that were only guessed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
+pub enum AddrSource {
+    /// Obtained from a successful IGD or NAT-PMP port mapping.
+    Mapped,
+    /// Inferred without a confirmed mapping (e.g. from the hole-punching path).
+    Guessed,
+}
+
+/// An advertised external endpoint tagged with how it was obtained.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct ExternalAddr {
+    /// The advertised endpoint.
+    pub endpoint: Endpoint,
+    /// Whether the endpoint is backed by a real mapping or merely guessed.
+    pub source: AddrSource,
+}
+
 /// Contact info generated by a call to `Service::prepare_contact_info`.
 #[derive(Debug)]
 pub struct OurContactInfo {
@@ -42,12 +61,14 @@ pub struct OurContactInfo {
     pub static_addrs: Vec<Endpoint>,
     /// The mapped addresses of our UDP socket.
     pub rendezvous_addrs: Vec<SocketAddr>,
+    /// Our external endpoints tagged with how each was obtained.
+    pub external_addrs: Vec<ExternalAddr>,
     /// TODO: documentation
     pub pub_key: PublicKey,
 }
 
 /// Contact info used to connect to another peer.
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
 pub struct TheirContactInfo {
     /// Secret data used for rendezvous connect.
     pub secret: Option<[u8; 4]>,
@@ -55,6 +76,9 @@ pub struct TheirContactInfo {
     pub static_addrs: Vec<Endpoint>,
     /// Their mapped addresses for UDP rendezvous connect.
     pub rendezvous_addrs: Vec<SocketAddr>,
+    /// Their external endpoints tagged with how each was obtained, so we can prioritise dialing
+    /// confirmed mappings.
+    pub external_addrs: Vec<ExternalAddr>,
     /// TODO: documentation
     pub pub_key: PublicKey,
 }
@@ -67,6 +91,7 @@ impl OurContactInfo {
             secret: self.secret.clone(),
             static_addrs: self.static_addrs.clone(),
             rendezvous_addrs: self.rendezvous_addrs.clone(),
+            external_addrs: self.external_addrs.clone(),
             pub_key: self.pub_key,
         }
     }
@@ -104,4 +129,12 @@ pub enum Event {
     ExternalEndpoints(Vec<Endpoint>),
     /// Invoked as a result to the call of `Service::prepare_contact_info`.
     ContactInfoPrepared(ContactInfoResult),
+    /// Invoked when our contact info was accepted by a rendezvous registration server.
+    Registered,
+    /// Invoked when a `Discover` against a rendezvous server returns a page of peers.  Passes the
+    /// contact info of each peer so the node can rendezvous-connect to them.
+    DiscoveredPeers(Vec<TheirContactInfo>),
+    /// Invoked when repeated handshake failures drive a peer's score to the ban threshold.  Passes
+    /// the peer's public key so the application can log or react.
+    PeerBanned(PublicKey),
 }
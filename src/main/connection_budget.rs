@@ -0,0 +1,201 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use main::PeerId;
+
+/// Default hard cap on the number of simultaneous in-flight handshakes, borrowed from devp2p's
+/// `MAX_CONNECTIONS`. Established connections are not counted against it.
+pub const MAX_CONNECTIONS: usize = 80;
+/// Default number of peers we actively try to keep, below the hard cap, borrowed from devp2p's
+/// `IDEAL_PEERS`.
+pub const IDEAL_PEERS: usize = 50;
+
+/// Which side initiated a handshake. Inbound and outbound connections are budgeted separately so a
+/// flood of inbound handshakes can never starve our ability to dial out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// A peer dialled us.
+    Inbound,
+    /// We dialled a peer.
+    Outbound,
+}
+
+struct Inner {
+    max_inbound: usize,
+    max_outbound: usize,
+    ideal_peers: usize,
+    reserved: HashSet<PeerId>,
+    // Live per-direction occupancy, counting in-flight handshakes only: a slot is released when
+    // the handshake ends, including on handoff to an active connection, so established
+    // connections are not counted. Maintained here on `admit`/`release` because
+    // `ConnectionMap`/`ConnectionId` record no direction, so it cannot be derived from the map.
+    inbound: usize,
+    outbound: usize,
+}
+
+impl Inner {
+    fn cap(&self, direction: Direction) -> usize {
+        match direction {
+            Direction::Inbound => self.max_inbound,
+            Direction::Outbound => self.max_outbound,
+        }
+    }
+
+    fn count(&self, direction: Direction) -> usize {
+        match direction {
+            Direction::Inbound => self.inbound,
+            Direction::Outbound => self.outbound,
+        }
+    }
+}
+
+/// Shared connection-budget accountant.
+///
+/// Consulted by `ConnectionCandidate::start` before a candidate is registered: if the per-direction
+/// budget is already full, the socket is dropped immediately and no candidate state is registered
+/// in `Core`. Inbound and outbound handshakes are counted and capped independently, so a
+/// flood of inbound handshakes can never consume the outbound budget and starve our ability to dial
+/// out. Reserved peers bypass the cap entirely so critical peers are never rejected under load.
+#[derive(Clone)]
+pub struct ConnectionBudget {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ConnectionBudget {
+    /// Creates a budget with the default `MAX_CONNECTIONS`/`IDEAL_PEERS` limits, split evenly
+    /// between inbound and outbound.
+    pub fn new() -> Self {
+        Self::with_limits(MAX_CONNECTIONS / 2, MAX_CONNECTIONS / 2, IDEAL_PEERS)
+    }
+
+    /// Creates a budget with explicit inbound/outbound hard caps and an ideal-peer target.
+    pub fn with_limits(max_inbound: usize, max_outbound: usize, ideal_peers: usize) -> Self {
+        ConnectionBudget {
+            inner: Arc::new(Mutex::new(Inner {
+                max_inbound: max_inbound,
+                max_outbound: max_outbound,
+                ideal_peers: ideal_peers,
+                reserved: HashSet::new(),
+                inbound: 0,
+                outbound: 0,
+            })),
+        }
+    }
+
+    /// Marks `peer` as reserved, exempting it from the connection cap.
+    pub fn reserve(&self, peer: PeerId) {
+        let _ = self.inner.lock().unwrap().reserved.insert(peer);
+    }
+
+    /// Removes `peer` from the reserved set.
+    pub fn unreserve(&self, peer: &PeerId) {
+        let _ = self.inner.lock().unwrap().reserved.remove(peer);
+    }
+
+    /// The number of peers we would like to maintain before we stop actively seeking more.
+    pub fn ideal_peers(&self) -> usize {
+        self.inner.lock().unwrap().ideal_peers
+    }
+
+    /// Returns `true` if a new `peer` connection in `direction` may be admitted. Reserved peers are
+    /// always admitted; everyone else is admitted only while that direction is below its own cap.
+    pub fn can_accept(&self, direction: Direction, peer: &PeerId) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.reserved.contains(peer) || inner.count(direction) < inner.cap(direction)
+    }
+
+    /// Records that a handshake in `direction` has been admitted, charging it against that
+    /// direction's budget. Paired with `release` when the handshake ends, whether it failed or was
+    /// handed off to an active connection.
+    pub fn admit(&self, direction: Direction) {
+        let mut inner = self.inner.lock().unwrap();
+        match direction {
+            Direction::Inbound => inner.inbound += 1,
+            Direction::Outbound => inner.outbound += 1,
+        }
+    }
+
+    /// Records that a previously admitted handshake in `direction` has ended, refunding its slot.
+    pub fn release(&self, direction: Direction) {
+        let mut inner = self.inner.lock().unwrap();
+        match direction {
+            Direction::Inbound => inner.inbound = inner.inbound.saturating_sub(1),
+            Direction::Outbound => inner.outbound = inner.outbound.saturating_sub(1),
+        }
+    }
+}
+
+impl Default for ConnectionBudget {
+    fn default() -> Self {
+        ConnectionBudget::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use main::PeerId;
+    use sodiumoxide::crypto::sign;
+
+    fn peer() -> PeerId {
+        PeerId(sign::gen_keypair().0)
+    }
+
+    #[test]
+    fn caps_are_tracked_per_direction() {
+        let budget = ConnectionBudget::with_limits(1, 1, 1);
+        let a = peer();
+        let b = peer();
+
+        assert!(budget.can_accept(Direction::Inbound, &a));
+        budget.admit(Direction::Inbound);
+        // The inbound slot is now full...
+        assert!(!budget.can_accept(Direction::Inbound, &b));
+        // ...but the outbound budget is counted separately and still has room.
+        assert!(budget.can_accept(Direction::Outbound, &b));
+
+        // Releasing the inbound slot reopens it.
+        budget.release(Direction::Inbound);
+        assert!(budget.can_accept(Direction::Inbound, &b));
+    }
+
+    #[test]
+    fn reserved_peers_bypass_the_cap() {
+        let budget = ConnectionBudget::with_limits(1, 1, 1);
+        let reserved = peer();
+        budget.reserve(reserved);
+
+        budget.admit(Direction::Inbound);
+        // A non-reserved peer is refused once the direction is full...
+        assert!(!budget.can_accept(Direction::Inbound, &peer()));
+        // ...while the reserved peer is admitted regardless.
+        assert!(budget.can_accept(Direction::Inbound, &reserved));
+
+        budget.unreserve(&reserved);
+        assert!(!budget.can_accept(Direction::Inbound, &reserved));
+    }
+
+    #[test]
+    fn release_never_underflows() {
+        let budget = ConnectionBudget::with_limits(2, 2, 1);
+        budget.release(Direction::Inbound);
+        assert!(budget.can_accept(Direction::Inbound, &peer()));
+    }
+}
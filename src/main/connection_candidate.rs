@@ -21,7 +21,10 @@ use std::collections::hash_map::Entry;
 use std::rc::Rc;
 
 use common::{Context, Core, Message, Priority, Socket, State};
-use main::{ConnectionId, ConnectionMap, PeerId};
+use event::Event;
+use main::{ConnectionBudget, ConnectionId, ConnectionMap, CrustEventSender, Direction, PeerId,
+           PeerScoreBoard};
+use main::peer_score::HANDSHAKE_FAILURE_PENALTY;
 use mio::{EventLoop, EventSet, PollOpt, Token};
 
 pub type Finish = Box<FnMut(&mut Core,
@@ -33,6 +36,10 @@ pub struct ConnectionCandidate {
     token: Token,
     context: Context,
     cm: ConnectionMap,
+    budget: ConnectionBudget,
+    direction: Direction,
+    scores: PeerScoreBoard,
+    event_tx: CrustEventSender,
     socket: Option<Socket>,
     their_id: PeerId,
     msg: Option<(Message, Priority)>,
@@ -45,10 +52,26 @@ impl ConnectionCandidate {
                  token: Token,
                  socket: Socket,
                  cm: ConnectionMap,
+                 budget: &ConnectionBudget,
+                 scores: &PeerScoreBoard,
+                 event_tx: &CrustEventSender,
+                 direction: Direction,
                  our_id: PeerId,
                  their_id: PeerId,
-                 finish: Finish)
+                 mut finish: Finish)
                  -> ::Res<Context> {
+        // Reject banned or below-threshold peers before any connection is built.
+        if scores.is_banned(&their_id) {
+            return Ok(Self::reject(core, el, &cm, socket, their_id, &mut finish));
+        }
+
+        // Reject surplus handshakes before any candidate state is registered for them. Reserved
+        // peers bypass the cap; everyone else is dropped immediately when that direction is over
+        // budget, mirroring the `finish(.., None)` path `handle_error` takes.
+        if !budget.can_accept(direction, &their_id) {
+            return Ok(Self::reject(core, el, &cm, socket, their_id, &mut finish));
+        }
+
         if our_id > their_id {
             try!(el.reregister(&socket,
                                token,
@@ -56,11 +79,19 @@ impl ConnectionCandidate {
                                PollOpt::edge()));
         }
 
+        // Charge the slot only once the candidate is certain to be created: a failure above returns
+        // before any state exists to later `release` it, which would leak the slot permanently.
+        budget.admit(direction);
+
         let context = core.get_new_context();
         let state = Rc::new(RefCell::new(ConnectionCandidate {
             token: token,
             context: context,
             cm: cm,
+            budget: budget.clone(),
+            direction: direction,
+            scores: scores.clone(),
+            event_tx: event_tx.clone(),
             socket: Some(socket),
             their_id: their_id,
             msg: Some((Message::ChooseConnection, 0)),
@@ -73,6 +104,24 @@ impl ConnectionCandidate {
         Ok(context)
     }
 
+    /// Drops a handshake that was refused before any candidate state was registered: deregister
+    /// the socket, undo the caller's `currently_handshaking` increment, and invoke `finish` with
+    /// `None`, as `handle_error`/`terminate` would for an admitted candidate. A fresh `Context` is
+    /// taken only to pass to `finish`; it is returned so `start` hands its caller the same one.
+    fn reject(core: &mut Core,
+              el: &mut EventLoop<Core>,
+              cm: &ConnectionMap,
+              socket: Socket,
+              their_id: PeerId,
+              finish: &mut Finish)
+              -> Context {
+        let _ = el.deregister(&socket);
+        decrement_handshaking(cm, their_id);
+        let context = core.get_new_context();
+        (*finish)(core, el, context, None);
+        context
+    }
+
     fn read(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
         match self.socket.as_mut().unwrap().read::<Message>() {
             Ok(Some(Message::ChooseConnection)) => self.done(core, el),
@@ -107,10 +156,22 @@ impl ConnectionCandidate {
         let context = self.context;
         let socket = self.socket.take().expect("Logic Error");
 
+        // The handshake slot admitted in `start` is refunded here as the socket is handed to the
+        // active connection; the active-connection state does its own `ConnectionMap` bookkeeping
+        // and carries no budget, so the slot must be released on this handoff or it would leak for
+        // the lifetime of the process.
+        self.budget.release(self.direction);
+
         (*self.finish)(core, el, context, Some((socket, token)));
     }
 
     fn handle_error(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        // A failed handshake debits the peer's reputation; enough failures will ban it so future
+        // attempts are short-circuited in `start`. When this failure is the one that crosses the
+        // ban threshold, let the application know.
+        if self.scores.report(self.their_id, HANDSHAKE_FAILURE_PENALTY) {
+            let _ = self.event_tx.send(Event::PeerBanned(self.their_id.pub_key()));
+        }
         self.terminate(core, el);
         let context = self.context;
         (*self.finish)(core, el, context, None);
@@ -140,16 +201,26 @@ impl State for ConnectionCandidate {
         let _ = core.remove_state(self.context);
         let _ = el.deregister(&self.socket.take().expect("Logic Error"));
 
-        let mut guard = self.cm.lock().unwrap();
-        if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
-            oe.get_mut().currently_handshaking -= 1;
-            if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
-                let _ = oe.remove();
-            }
-        }
+        // The handshake slot admitted in `start` is refunded here on a failed/abandoned handshake;
+        // the successful `done` path refunds it on the handoff to the active connection.
+        self.budget.release(self.direction);
+        decrement_handshaking(&self.cm, self.their_id);
     }
 
     fn as_any(&mut self) -> &mut Any {
         self
     }
+}
+
+/// Undoes one `currently_handshaking` increment for `their_id`, removing the map entry if it is no
+/// longer tracking any handshake or active connection. Shared by `terminate` and the early
+/// rejection paths so both leave `ConnectionMap` bookkeeping consistent.
+fn decrement_handshaking(cm: &ConnectionMap, their_id: PeerId) {
+    let mut guard = cm.lock().unwrap();
+    if let Entry::Occupied(mut oe) = guard.entry(their_id) {
+        oe.get_mut().currently_handshaking -= 1;
+        if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
+            let _ = oe.remove();
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,447 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use common::{Context, Core, State};
+use event::TheirContactInfo;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use main::{ConnectionId, ConnectionMap, PeerId};
+use mio::{EventLoop, EventSet, PollOpt, Token};
+use mio::unix::{UnixListener, UnixStream};
+use sodiumoxide::crypto::sign::PublicKey;
+
+/// A command sent by an operator over the control socket.
+///
+/// Modelled on WireGuard-rs's config service, which accepts `Set`/`Get` commands over a Unix
+/// socket.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum Command {
+    /// Mutate the running service.
+    Set(SetCommand),
+    /// Query the running service.
+    Get(GetCommand),
+}
+
+/// The mutating half of the control protocol.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum SetCommand {
+    /// Add a peer's contact info and immediately attempt to connect to it.
+    AddPeer(TheirContactInfo),
+    /// Drop the peer with the given public key.
+    RemovePeer(PublicKey),
+    /// Change the set of bootstrap contacts used on (re)bootstrap.
+    Bootstrap(Vec<TheirContactInfo>),
+    /// Change the TCP listening port.
+    ListenPort(u16),
+}
+
+/// The querying half of the control protocol.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum GetCommand {
+    /// List every peer the connection map is tracking, with its state.
+    ListConnections,
+}
+
+/// A change streamed back to the operator, either as the effect of a `Set` or the contents of a
+/// `Get`. Named after WireGuard-rs's `UpdateEvent`s.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum UpdateEvent {
+    /// A peer was added or its state changed.
+    UpdatePeer {
+        /// The peer's public key.
+        pub_key: PublicKey,
+        /// `true` while the connection is still handshaking, `false` once it is active.
+        handshaking: bool,
+    },
+    /// A peer was removed.
+    RemovePeer(PublicKey),
+    /// The listening port was changed.
+    ListenPort(u16),
+    /// The command completed; carries a human-readable status.
+    Done(String),
+    /// The command failed; carries a human-readable error.
+    Error(String),
+}
+
+/// Applies control commands to the running `Service`. Implemented by the service so that all
+/// mutations happen on the event-loop thread, where the `Core`/`EventLoop` may be touched safely.
+pub trait ControlHandler {
+    /// Apply a single `Set` command, streaming back the resulting `UpdateEvent`s.
+    fn handle_set(&mut self,
+                  core: &mut Core,
+                  el: &mut EventLoop<Core>,
+                  cmd: SetCommand)
+                  -> Vec<UpdateEvent>;
+}
+
+/// Optional control socket attached to a running `Service`.
+///
+/// A thin mio `State` registered under its own `Token`, so commands read off the Unix socket are
+/// routed into the `Core`/`EventLoop` on the event-loop thread. Mutations are delegated to a
+/// `ControlHandler`; queries are answered directly from the shared `ConnectionMap`. Applied changes
+/// are serialised straight back over the socket.
+pub struct ControlInterface {
+    token: Token,
+    context: Context,
+    listener: UnixListener,
+    cm: ConnectionMap,
+    handler: Rc<RefCell<ControlHandler>>,
+    path: PathBuf,
+}
+
+impl ControlInterface {
+    /// Binds a control socket at `path` and registers it with the event loop. The socket is
+    /// removed from the filesystem when the interface terminates.
+    pub fn start(core: &mut Core,
+                 el: &mut EventLoop<Core>,
+                 path: PathBuf,
+                 cm: ConnectionMap,
+                 handler: Rc<RefCell<ControlHandler>>)
+                 -> ::Res<Context> {
+        // A stale socket file from a previous run would make `bind` fail; clear it first.
+        let _ = ::std::fs::remove_file(&path);
+        let listener = try!(UnixListener::bind(&path));
+
+        let token = core.get_new_token();
+        let context = core.get_new_context();
+
+        try!(el.register(&listener,
+                         token,
+                         EventSet::readable() | EventSet::error() | EventSet::hup(),
+                         PollOpt::edge()));
+
+        let state = Rc::new(RefCell::new(ControlInterface {
+            token: token,
+            context: context,
+            listener: listener,
+            cm: cm,
+            handler: handler,
+            path: path,
+        }));
+
+        let _ = core.insert_context(token, context);
+        let _ = core.insert_state(context, state);
+
+        Ok(context)
+    }
+
+    /// Accepts every pending client and hands each to its own `ControlConnection` state, so reads
+    /// are driven incrementally from the event loop rather than blocking it.
+    fn accept(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        loop {
+            match self.listener.accept() {
+                Ok(Some(stream)) => {
+                    let _ = ControlConnection::start(core,
+                                                     el,
+                                                     stream,
+                                                     self.cm.clone(),
+                                                     self.handler.clone());
+                }
+                Ok(None) => break,
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl State for ControlInterface {
+    fn ready(&mut self,
+             core: &mut Core,
+             el: &mut EventLoop<Core>,
+             _token: Token,
+             event_set: EventSet) {
+        if event_set.is_error() || event_set.is_hup() {
+            return self.terminate(core, el);
+        }
+        if event_set.is_readable() {
+            self.accept(core, el);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let _ = core.remove_context(self.token);
+        let _ = core.remove_state(self.context);
+        let _ = el.deregister(&self.listener);
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// A single connected control client.
+///
+/// Registered under its own `Token` and driven from `ready`, so a client that sends a partial
+/// request never blocks the event loop: bytes are accumulated across readable events until the
+/// length-prefixed `Command` is complete, then applied and its `UpdateEvent`s flushed back on
+/// writable events. One request/response per connection keeps the framing trivial.
+struct ControlConnection {
+    token: Token,
+    context: Context,
+    stream: UnixStream,
+    cm: ConnectionMap,
+    handler: Rc<RefCell<ControlHandler>>,
+    read_buf: Vec<u8>,
+    msg_len: Option<usize>,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl ControlConnection {
+    fn start(core: &mut Core,
+             el: &mut EventLoop<Core>,
+             stream: UnixStream,
+             cm: ConnectionMap,
+             handler: Rc<RefCell<ControlHandler>>)
+             -> ::Res<Context> {
+        let token = core.get_new_token();
+        let context = core.get_new_context();
+
+        try!(el.register(&stream,
+                         token,
+                         EventSet::readable() | EventSet::error() | EventSet::hup(),
+                         PollOpt::edge()));
+
+        let state = Rc::new(RefCell::new(ControlConnection {
+            token: token,
+            context: context,
+            stream: stream,
+            cm: cm,
+            handler: handler,
+            read_buf: Vec::new(),
+            msg_len: None,
+            write_buf: Vec::new(),
+            write_pos: 0,
+        }));
+
+        let _ = core.insert_context(token, context);
+        let _ = core.insert_state(context, state);
+
+        Ok(context)
+    }
+
+    /// Drains whatever bytes are ready without blocking, then applies the request once the whole
+    /// length-prefixed frame has arrived.
+    fn read(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return self.terminate(core, el),
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return self.terminate(core, el),
+            }
+        }
+
+        if self.msg_len.is_none() && self.read_buf.len() >= 4 {
+            let len = read_u32_le(&self.read_buf) as usize;
+            self.read_buf.drain(..4);
+            self.msg_len = Some(len);
+        }
+
+        if let Some(len) = self.msg_len {
+            if self.read_buf.len() >= len {
+                let body: Vec<u8> = self.read_buf.drain(..len).collect();
+                self.apply(core, el, &body);
+            }
+        }
+    }
+
+    /// Applies a fully received request and queues its response for writing.
+    fn apply(&mut self, core: &mut Core, el: &mut EventLoop<Core>, body: &[u8]) {
+        let updates = match deserialise::<Command>(body) {
+            Ok(Command::Set(cmd)) => self.handler.borrow_mut().handle_set(core, el, cmd),
+            Ok(Command::Get(GetCommand::ListConnections)) => self.list_connections(),
+            Err(e) => vec![UpdateEvent::Error(format!("{}", e))],
+        };
+        self.write_buf = encode_updates(&updates);
+        if self.flush() {
+            return self.terminate(core, el);
+        }
+        let _ = el.reregister(&self.stream,
+                              self.token,
+                              EventSet::writable() | EventSet::error() | EventSet::hup(),
+                              PollOpt::edge());
+    }
+
+    /// Writes as much of the queued response as the socket will take without blocking; returns
+    /// `true` once the whole response has been flushed or a write error ends the exchange.
+    fn flush(&mut self) -> bool {
+        while self.write_pos < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[self.write_pos..]) {
+                Ok(0) => return false,
+                Ok(n) => self.write_pos += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(_) => return true,
+            }
+        }
+        true
+    }
+
+    /// Snapshots the connection map into `UpdatePeer` events, one per tracked peer.
+    fn list_connections(&self) -> Vec<UpdateEvent> {
+        let guard = self.cm.lock().unwrap();
+        let mut updates: Vec<UpdateEvent> = guard.iter()
+            .map(|(peer, &ConnectionId { active_connection, .. })| {
+                UpdateEvent::UpdatePeer {
+                    pub_key: peer.pub_key(),
+                    handshaking: active_connection.is_none(),
+                }
+            })
+            .collect();
+        updates.push(UpdateEvent::Done(format!("{} connection(s)", updates.len())));
+        updates
+    }
+}
+
+impl State for ControlConnection {
+    fn ready(&mut self,
+             core: &mut Core,
+             el: &mut EventLoop<Core>,
+             _token: Token,
+             event_set: EventSet) {
+        if event_set.is_error() || event_set.is_hup() {
+            return self.terminate(core, el);
+        }
+        if event_set.is_readable() {
+            self.read(core, el);
+        }
+        if event_set.is_writable() && self.flush() {
+            self.terminate(core, el);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let _ = core.remove_context(self.token);
+        let _ = core.remove_state(self.context);
+        let _ = el.deregister(&self.stream);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Serialises `updates` into a length-prefixed frame, or an empty buffer on failure.
+fn encode_updates(updates: &[UpdateEvent]) -> Vec<u8> {
+    let payload = match serialise(updates) {
+        Ok(payload) => payload,
+        Err(_) => return Vec::new(),
+    };
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    let _ = frame.write_u32::<LittleEndian>(payload.len() as u32);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Reads a little-endian `u32` from the start of `bytes`, which must hold at least four.
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    let mut cursor = bytes;
+    cursor.read_u32::<LittleEndian>().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use sodiumoxide::crypto::sign;
+
+    use event::TheirContactInfo;
+
+    fn contact_info() -> TheirContactInfo {
+        TheirContactInfo {
+            secret: None,
+            static_addrs: vec![],
+            rendezvous_addrs: vec![],
+            external_addrs: vec![],
+            pub_key: sign::gen_keypair().0,
+        }
+    }
+
+    /// A `ControlHandler` that records the `Set` commands routed to it, standing in for the
+    /// `Service` so the routing contract can be exercised without an event loop.
+    struct RecordingHandler {
+        applied: Vec<SetCommand>,
+    }
+
+    impl RecordingHandler {
+        /// The command-handling logic, factored out of `handle_set` so it can be exercised without
+        /// a `Core`/`EventLoop`.
+        fn handle_set_unchecked(&mut self, cmd: SetCommand) -> Vec<UpdateEvent> {
+            let ack = UpdateEvent::Done(format!("{:?}", cmd));
+            self.applied.push(cmd);
+            vec![ack]
+        }
+    }
+
+    impl ControlHandler for RecordingHandler {
+        fn handle_set(&mut self,
+                      _core: &mut Core,
+                      _el: &mut EventLoop<Core>,
+                      cmd: SetCommand)
+                      -> Vec<UpdateEvent> {
+            self.handle_set_unchecked(cmd)
+        }
+    }
+
+    #[test]
+    fn set_commands_round_trip_over_the_wire() {
+        let cmds = vec![Command::Set(SetCommand::AddPeer(contact_info())),
+                        Command::Set(SetCommand::RemovePeer(sign::gen_keypair().0)),
+                        Command::Set(SetCommand::ListenPort(1234)),
+                        Command::Get(GetCommand::ListConnections)];
+        for cmd in cmds {
+            let bytes = unwrap_result!(serialise(&cmd));
+            let decoded: Command = unwrap_result!(deserialise(&bytes));
+            // Re-serialising the decoded command yields the same bytes, proving a lossless frame.
+            assert_eq!(bytes, unwrap_result!(serialise(&decoded)));
+        }
+    }
+
+    #[test]
+    fn encode_updates_is_length_prefixed() {
+        let updates = vec![UpdateEvent::ListenPort(4000), UpdateEvent::Done("ok".to_owned())];
+        let frame = encode_updates(&updates);
+        assert!(frame.len() >= 4);
+        let len = read_u32_le(&frame) as usize;
+        assert_eq!(len, frame.len() - 4);
+        let decoded: Vec<UpdateEvent> = unwrap_result!(deserialise(&frame[4..]));
+        assert_eq!(decoded.len(), updates.len());
+    }
+
+    #[test]
+    fn recording_handler_captures_applied_commands() {
+        // Exercises the `ControlHandler` contract without an event loop: a command handed to the
+        // handler is both recorded and acknowledged with a `Done` update. Driving the full socket
+        // path through `apply()` needs a live `Core`/`EventLoop`, which these unit tests cannot
+        // construct.
+        let mut handler = RecordingHandler { applied: vec![] };
+        let updates = handler.handle_set_unchecked(SetCommand::ListenPort(7000));
+        assert_eq!(handler.applied.len(), 1);
+        match updates.first() {
+            Some(&UpdateEvent::Done(_)) => (),
+            other => panic!("expected a Done ack, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use event::TheirContactInfo;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use sodiumoxide::crypto::sign::PublicKey;
+
+/// Maximum number of peers retained on disk. When the table is full the lowest-scored entry is
+/// evicted, mirroring devp2p's bounded `node_table`.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// A single remembered peer: how to reach it plus the connection statistics used to score it,
+/// after devp2p's `node_table` entries and `NetworkStats`.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct NodeEntry {
+    /// The contact info used to reconnect to this peer.
+    pub info: TheirContactInfo,
+    /// Seconds since the Unix epoch at which we last had a connection to this peer.
+    pub last_seen_secs: u64,
+    /// Number of successful connections to this peer.
+    pub success_count: u32,
+    /// Number of failed connections to this peer.
+    pub failure_count: u32,
+}
+
+impl NodeEntry {
+    /// A simple reputation score: successes weigh positively, failures negatively, with recency as
+    /// a pure tie-break. Returned as a `(reliability, last_seen_secs)` tuple so reliability always
+    /// orders first and the epoch-scale recency term can only separate peers of equal reliability
+    /// — ordering both reconnection attempts and eviction-victim selection.
+    pub fn score(&self) -> (i64, u64) {
+        let reliability = self.success_count as i64 * 2 - self.failure_count as i64;
+        (reliability, self.last_seen_secs)
+    }
+}
+
+/// Persistent address book.
+///
+/// Remembers the `TheirContactInfo` of peers we have successfully connected to, keyed by
+/// `PublicKey`, so a node can re-form its mesh across restarts instead of relying on a fresh
+/// bootstrap each time. The table is capped and evicts the lowest-scored entry when full.
+pub struct NodeTable {
+    path: PathBuf,
+    capacity: usize,
+    entries: HashMap<PublicKey, NodeEntry>,
+}
+
+impl NodeTable {
+    /// Loads the table from `path`, or starts an empty one if the file is missing or unreadable.
+    /// A corrupt file is treated as empty rather than failing startup.
+    pub fn load(path: PathBuf) -> Self {
+        Self::load_with_capacity(path, DEFAULT_CAPACITY)
+    }
+
+    /// As `load`, with an explicit capacity.
+    pub fn load_with_capacity(path: PathBuf, capacity: usize) -> Self {
+        let entries = read_entries(&path).unwrap_or_default();
+        NodeTable {
+            path: path,
+            capacity: capacity,
+            entries: entries,
+        }
+    }
+
+    /// Records a successful connection to the peer described by `info`, bumping its success count
+    /// and last-seen time. Inserts a new entry (evicting the weakest if full) if the peer is unseen.
+    pub fn note_connected(&mut self, info: &TheirContactInfo) {
+        let now = now_secs();
+        let key = info.pub_key;
+        {
+            let entry = self.entries.entry(key).or_insert_with(|| NodeEntry {
+                info: info.clone(),
+                last_seen_secs: now,
+                success_count: 0,
+                failure_count: 0,
+            });
+            entry.info = info.clone();
+            entry.last_seen_secs = now;
+            entry.success_count = entry.success_count.saturating_add(1);
+        }
+        self.evict_if_full(&key);
+    }
+
+    /// Records a lost connection or failed reconnection for `pub_key`, bumping its failure count.
+    /// Unknown peers are ignored.
+    pub fn note_lost(&mut self, pub_key: &PublicKey) {
+        if let Some(entry) = self.entries.get_mut(pub_key) {
+            entry.failure_count = entry.failure_count.saturating_add(1);
+        }
+    }
+
+    /// Returns the remembered peers, highest-scored first, for use as reconnection targets on
+    /// startup before falling back to configured bootstrap nodes.
+    pub fn reconnect_candidates(&self) -> Vec<TheirContactInfo> {
+        let mut entries: Vec<&NodeEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| b.score().cmp(&a.score()));
+        entries.into_iter().map(|e| e.info.clone()).collect()
+    }
+
+    /// Persists the current table to disk. Written to a temporary file and renamed so a crash
+    /// mid-write cannot corrupt the existing table.
+    pub fn save(&self) -> io::Result<()> {
+        let bytes = try!(serialise(&self.entries)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))));
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(&bytes));
+            try!(file.sync_all());
+        }
+        ::std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Evicts the lowest-scored entry if the table has grown past its capacity. The just-touched
+    /// `keep` key is never chosen as the victim.
+    fn evict_if_full(&mut self, keep: &PublicKey) {
+        while self.entries.len() > self.capacity {
+            let victim = self.entries
+                .iter()
+                .filter(|&(k, _)| k != keep)
+                .min_by_key(|&(_, e)| e.score())
+                .map(|(k, _)| *k);
+            match victim {
+                Some(key) => {
+                    let _ = self.entries.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Current wall-clock time in seconds since the Unix epoch, saturating at zero if the clock is
+/// before the epoch.
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Reads and deserialises the table at `path`, returning `None` if it is missing or corrupt.
+fn read_entries(path: &Path) -> Option<HashMap<PublicKey, NodeEntry>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    let mut bytes = Vec::new();
+    if file.read_to_end(&mut bytes).is_err() {
+        return None;
+    }
+    deserialise(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use event::TheirContactInfo;
+    use sodiumoxide::crypto::sign;
+
+    fn contact_info() -> TheirContactInfo {
+        TheirContactInfo {
+            secret: None,
+            static_addrs: vec![],
+            rendezvous_addrs: vec![],
+            external_addrs: vec![],
+            pub_key: sign::gen_keypair().0,
+        }
+    }
+
+    fn temp_path(tag: &str) -> PathBuf {
+        let mut path = env::temp_dir();
+        let stamp = sign::gen_keypair().0;
+        path.push(format!("crust_node_table_{}_{:?}.bin", tag, &stamp.0[..4]));
+        path
+    }
+
+    #[test]
+    fn score_orders_reliability_before_recency() {
+        let reliable_but_stale = NodeEntry {
+            info: contact_info(),
+            last_seen_secs: 0,
+            success_count: 5,
+            failure_count: 0,
+        };
+        let fresh_but_unreliable = NodeEntry {
+            info: contact_info(),
+            last_seen_secs: u64::max_value(),
+            success_count: 0,
+            failure_count: 0,
+        };
+        assert!(reliable_but_stale.score() > fresh_but_unreliable.score());
+
+        // Among equally reliable peers, the more recently seen one wins the tie-break.
+        let older = NodeEntry {
+            info: contact_info(),
+            last_seen_secs: 100,
+            success_count: 3,
+            failure_count: 0,
+        };
+        let newer = NodeEntry {
+            info: contact_info(),
+            last_seen_secs: 200,
+            success_count: 3,
+            failure_count: 0,
+        };
+        assert!(newer.score() > older.score());
+    }
+
+    #[test]
+    fn reconnect_candidates_are_ordered_by_score() {
+        let mut table = NodeTable::load_with_capacity(temp_path("order"), 16);
+        let weak = contact_info();
+        let strong = contact_info();
+        table.note_connected(&weak);
+        table.note_connected(&strong);
+        table.note_connected(&strong);
+        table.note_lost(&weak.pub_key);
+
+        let candidates = table.reconnect_candidates();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].pub_key, strong.pub_key);
+    }
+
+    #[test]
+    fn lru_eviction_drops_the_weakest_entry() {
+        let mut table = NodeTable::load_with_capacity(temp_path("lru"), 2);
+        let a = contact_info();
+        let b = contact_info();
+        let c = contact_info();
+
+        table.note_connected(&a);
+        table.note_connected(&a);
+        table.note_connected(&b);
+        // `c` is the freshly touched key, so `b` (single success) is the eviction victim, not `a`.
+        table.note_connected(&c);
+
+        let keys: Vec<_> = table.reconnect_candidates().into_iter().map(|i| i.pub_key).collect();
+        assert!(keys.contains(&a.pub_key));
+        assert!(keys.contains(&c.pub_key));
+        assert!(!keys.contains(&b.pub_key));
+    }
+
+    #[test]
+    fn save_and_load_round_trips() {
+        let path = temp_path("roundtrip");
+        let info = contact_info();
+        {
+            let mut table = NodeTable::load(path.clone());
+            table.note_connected(&info);
+            unwrap_result!(table.save());
+        }
+        let reloaded = NodeTable::load(path.clone());
+        let candidates = reloaded.reconnect_candidates();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].pub_key, info.pub_key);
+        let _ = ::std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_file_is_treated_as_empty() {
+        let path = temp_path("corrupt");
+        {
+            let mut file = unwrap_result!(File::create(&path));
+            unwrap_result!(file.write_all(b"not a valid table"));
+        }
+        let table = NodeTable::load(path.clone());
+        assert!(table.reconnect_candidates().is_empty());
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
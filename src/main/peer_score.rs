@@ -0,0 +1,177 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use main::PeerId;
+
+/// Score below which a peer is refused a new connection.
+pub const BAN_THRESHOLD: f64 = -100.0;
+/// Score debited each time a handshake to a peer fails.
+pub const HANDSHAKE_FAILURE_PENALTY: f64 = -20.0;
+/// How quickly a peer's score decays back towards zero, in points per second. Penalised peers are
+/// gradually forgiven so a transient problem does not ban a peer forever.
+const DECAY_PER_SEC: f64 = 1.0;
+
+struct Entry {
+    score: f64,
+    updated_at: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Entry {
+    fn new(now: Instant) -> Self {
+        Entry {
+            score: 0.0,
+            updated_at: now,
+            banned_until: None,
+        }
+    }
+
+    /// Decays the score towards zero according to the elapsed time since it was last touched.
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        let decay = DECAY_PER_SEC * secs;
+        if self.score > 0.0 {
+            self.score = (self.score - decay).max(0.0);
+        } else if self.score < 0.0 {
+            self.score = (self.score + decay).min(0.0);
+        }
+        self.updated_at = now;
+    }
+}
+
+/// Shared peer reputation table.
+///
+/// Keyed by `PeerId`, it holds a decaying score per peer plus an optional explicit ban window.
+/// `ConnectionCandidate::start` consults it and short-circuits to `finish(.., None)` for banned or
+/// below-threshold peers, rejecting them before any connection is built. Repeated handshake
+/// failures debit the score automatically, so persistently misbehaving peers ban themselves.
+///
+/// This mirrors Lighthouse moving banning down to the behaviour level.
+#[derive(Clone, Default)]
+pub struct PeerScoreBoard {
+    inner: Arc<Mutex<HashMap<PeerId, Entry>>>,
+}
+
+impl PeerScoreBoard {
+    /// Creates an empty score board.
+    pub fn new() -> Self {
+        PeerScoreBoard { inner: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Adjusts `peer`'s score by `delta` (negative to penalise). Returns `true` if this pushed the
+    /// peer at or below the ban threshold for the first time, so the caller can emit
+    /// `Event::PeerBanned`.
+    pub fn report(&self, peer: PeerId, delta: f64) -> bool {
+        let now = Instant::now();
+        let mut guard = self.inner.lock().unwrap();
+        let entry = guard.entry(peer).or_insert_with(|| Entry::new(now));
+        entry.decay(now);
+        let was_ok = entry.score > BAN_THRESHOLD && entry.banned_until.is_none();
+        entry.score += delta;
+        was_ok && entry.score <= BAN_THRESHOLD
+    }
+
+    /// Explicitly bans `peer` for `duration`, regardless of its score.
+    pub fn ban(&self, peer: PeerId, duration: Duration) {
+        let now = Instant::now();
+        let mut guard = self.inner.lock().unwrap();
+        let entry = guard.entry(peer).or_insert_with(|| Entry::new(now));
+        entry.banned_until = Some(now + duration);
+    }
+
+    /// Returns `true` if `peer` is currently banned, either explicitly or because its (decayed)
+    /// score has fallen to the ban threshold. Expired explicit bans are cleared as a side effect.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        let now = Instant::now();
+        let mut guard = self.inner.lock().unwrap();
+        let entry = match guard.get_mut(peer) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if let Some(until) = entry.banned_until {
+            if until > now {
+                return true;
+            }
+            entry.banned_until = None;
+        }
+        entry.decay(now);
+        entry.score <= BAN_THRESHOLD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    use main::PeerId;
+    use sodiumoxide::crypto::sign;
+
+    fn peer() -> PeerId {
+        PeerId(sign::gen_keypair().0)
+    }
+
+    #[test]
+    fn report_signals_only_the_first_ban_threshold_crossing() {
+        let board = PeerScoreBoard::new();
+        let peer = peer();
+
+        // A debit that stays above the threshold is not a crossing.
+        assert!(!board.report(peer, -50.0));
+        // The debit that drops the score to/below the threshold is the crossing.
+        assert!(board.report(peer, -60.0));
+        assert!(board.is_banned(&peer));
+        // Further debits while already banned must not re-signal.
+        assert!(!board.report(peer, -20.0));
+    }
+
+    #[test]
+    fn handshake_failures_accumulate_to_a_ban() {
+        let board = PeerScoreBoard::new();
+        let peer = peer();
+        let mut crossed = false;
+        // BAN_THRESHOLD / HANDSHAKE_FAILURE_PENALTY failures would land exactly on the threshold,
+        // but the score decays slightly between reports, so one more failure is needed to cross.
+        for _ in 0..(BAN_THRESHOLD / HANDSHAKE_FAILURE_PENALTY).ceil() as usize + 1 {
+            crossed |= board.report(peer, HANDSHAKE_FAILURE_PENALTY);
+        }
+        assert!(crossed);
+        assert!(board.is_banned(&peer));
+    }
+
+    #[test]
+    fn unknown_peer_is_not_banned() {
+        let board = PeerScoreBoard::new();
+        assert!(!board.is_banned(&peer()));
+    }
+
+    #[test]
+    fn explicit_ban_expires() {
+        let board = PeerScoreBoard::new();
+        let peer = peer();
+        board.ban(peer, Duration::from_millis(50));
+        assert!(board.is_banned(&peer));
+        thread::sleep(Duration::from_millis(80));
+        assert!(!board.is_banned(&peer));
+    }
+}
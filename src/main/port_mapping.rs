@@ -0,0 +1,207 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::net::SocketAddr as StdSocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use endpoint::Endpoint;
+use event::{AddrSource, ExternalAddr};
+use maidsafe_utilities::thread::named as spawn_named;
+use socket_addr::SocketAddr;
+
+/// Transport of a port mapping request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    /// TCP, mapped for the listener.
+    Tcp,
+    /// UDP, mapped for the rendezvous socket.
+    Udp,
+}
+
+/// A public address together with how it was obtained.
+#[derive(Clone, Copy, Debug)]
+pub struct MappedAddr {
+    /// The public socket address.
+    pub addr: StdSocketAddr,
+    /// Whether this address is backed by a real mapping.
+    pub source: AddrSource,
+}
+
+/// Best-effort external-address discovery, after devp2p's `map_external_address` /
+/// `select_public_address`.
+///
+/// During `prepare_contact_info` this asks the gateway, over UPnP-IGD first and NAT-PMP as a
+/// fallback, to map the TCP listener and the rendezvous UDP socket, so the resulting public
+/// addresses can be folded into `static_addrs` / `rendezvous_addrs` and emitted as
+/// `Event::ExternalEndpoints`. Every query runs under a timeout so it can never block contact-info
+/// preparation. Each mapping is requested with the lease returned by `lease()`; this type does not
+/// schedule lease refreshes itself.
+pub struct PortMapper {
+    timeout: Duration,
+    lease: Duration,
+}
+
+impl PortMapper {
+    /// Creates a port mapper with the default best-effort timeout and lease duration.
+    pub fn new() -> Self {
+        PortMapper {
+            timeout: Duration::from_secs(3),
+            lease: Duration::from_secs(3600),
+        }
+    }
+
+    /// The lease duration requested for each mapping; callers refresh on this interval.
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+
+    /// Attempts to map `local` for `protocol`, returning the public address if a gateway responded
+    /// within the timeout. IGD is tried first; on failure NAT-PMP is tried with the remaining time.
+    pub fn map(&self, protocol: Protocol, local: StdSocketAddr) -> Option<MappedAddr> {
+        let lease = self.lease;
+        let run = move || {
+            map_via_igd(protocol, local, lease).or_else(|| map_via_natpmp(protocol, local, lease))
+        };
+        with_timeout(self.timeout, run).map(|addr| MappedAddr {
+            addr: addr,
+            source: AddrSource::Mapped,
+        })
+    }
+
+    /// Maps the TCP listener and UDP rendezvous socket, folding any mapped public addresses into
+    /// the supplied `static_addrs` / `rendezvous_addrs` and recording the provenance of every
+    /// advertised endpoint in `external_addrs`. Mapped addresses are placed first so peers dial
+    /// them ahead of guessed ones. Returns the full list of external endpoints — mapped first, then
+    /// guessed — for `Event::ExternalEndpoints`.
+    pub fn augment_contact_info(&self,
+                                tcp_listener: StdSocketAddr,
+                                udp_socket: StdSocketAddr,
+                                static_addrs: &mut Vec<Endpoint>,
+                                rendezvous_addrs: &mut Vec<SocketAddr>,
+                                external_addrs: &mut Vec<ExternalAddr>)
+                                -> Vec<Endpoint> {
+        // Whatever is already present came from the hole-punching path, i.e. it was guessed.
+        let mut guessed: Vec<Endpoint> = static_addrs.iter().cloned().collect();
+        guessed.extend(rendezvous_addrs.iter().map(|addr| Endpoint::from_socket_addr(addr.0)));
+        for endpoint in &guessed {
+            external_addrs.push(ExternalAddr {
+                endpoint: endpoint.clone(),
+                source: AddrSource::Guessed,
+            });
+        }
+
+        let mut external = Vec::new();
+        if let Some(mapped) = self.map(Protocol::Tcp, tcp_listener) {
+            let endpoint = Endpoint::from_socket_addr(mapped.addr);
+            static_addrs.insert(0, endpoint.clone());
+            external_addrs.push(ExternalAddr {
+                endpoint: endpoint.clone(),
+                source: mapped.source,
+            });
+            external.push(endpoint);
+        }
+        if let Some(mapped) = self.map(Protocol::Udp, udp_socket) {
+            rendezvous_addrs.insert(0, SocketAddr(mapped.addr));
+            let endpoint = Endpoint::from_socket_addr(mapped.addr);
+            external_addrs.push(ExternalAddr {
+                endpoint: endpoint.clone(),
+                source: mapped.source,
+            });
+            external.push(endpoint);
+        }
+
+        external.extend(guessed);
+        external
+    }
+}
+
+impl Default for PortMapper {
+    fn default() -> Self {
+        PortMapper::new()
+    }
+}
+
+/// Runs `f` on a worker thread and waits up to `timeout` for its result, discarding a late answer.
+/// This keeps a slow or unreachable gateway from blocking contact-info preparation.
+fn with_timeout<F>(timeout: Duration, f: F) -> Option<StdSocketAddr>
+    where F: FnOnce() -> Option<StdSocketAddr> + Send + 'static
+{
+    let (tx, rx) = mpsc::channel();
+    let _ = spawn_named("CrustPortMapper", move || {
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => None,
+    }
+}
+
+/// Requests a mapping over UPnP-IGD. Returns the public address on success.
+fn map_via_igd(protocol: Protocol, local: StdSocketAddr, lease: Duration) -> Option<StdSocketAddr> {
+    use igd::{search_gateway, PortMappingProtocol};
+
+    let gateway = match search_gateway() {
+        Ok(gateway) => gateway,
+        Err(_) => return None,
+    };
+    let igd_protocol = match protocol {
+        Protocol::Tcp => PortMappingProtocol::TCP,
+        Protocol::Udp => PortMappingProtocol::UDP,
+    };
+    let external_ip = match gateway.get_external_ip() {
+        Ok(ip) => ip,
+        Err(_) => return None,
+    };
+    match gateway.add_port(igd_protocol,
+                           local.port(),
+                           local,
+                           lease.as_secs() as u32,
+                           "crust") {
+        Ok(()) => Some(StdSocketAddr::new(external_ip.into(), local.port())),
+        Err(_) => None,
+    }
+}
+
+/// Requests a mapping over NAT-PMP. Returns the public address on success.
+fn map_via_natpmp(protocol: Protocol,
+                  local: StdSocketAddr,
+                  lease: Duration)
+                  -> Option<StdSocketAddr> {
+    use natpmp::{Natpmp, Protocol as NatpmpProtocol};
+
+    let mut n = match Natpmp::new() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+    let proto = match protocol {
+        Protocol::Tcp => NatpmpProtocol::TCP,
+        Protocol::Udp => NatpmpProtocol::UDP,
+    };
+    if n.send_port_mapping_request(proto, local.port(), local.port(), lease.as_secs() as u32)
+        .is_err() {
+        return None;
+    }
+    if n.send_public_address_request().is_err() {
+        return None;
+    }
+    match n.read_response_or_retry() {
+        Ok(response) => Some(StdSocketAddr::new(response.public_address().into(), local.port())),
+        Err(_) => None,
+    }
+}
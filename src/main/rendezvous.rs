@@ -0,0 +1,522 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use common::{Context, Core, Message, Priority, Socket, State};
+use event::{Event, TheirContactInfo};
+use maidsafe_utilities::serialisation::serialise;
+use mio::{EventLoop, EventSet, PollOpt, Token};
+use sodiumoxide::crypto::sign::PublicKey;
+
+/// Priority of the rendezvous control messages. These are small and infrequent so they ride just
+/// below user data.
+const RENDEZVOUS_MSG_PRIORITY: Priority = 1;
+
+/// A single namespace registration held by a `RendezvousServer`.
+struct Registration {
+    info: TheirContactInfo,
+    expires_at: Instant,
+    /// Monotonic sequence number assigned when the entry was (re-)registered. Discovery pages by
+    /// this rather than by position so a client can poll for peers that register later.
+    seq: u64,
+}
+
+/// In-band rendezvous registration server.
+///
+/// Brokers `TheirContactInfo` blobs between peers so that a node can discover others to
+/// rendezvous-connect to without any external channel. The server keeps a per-namespace map of
+/// registrations keyed by the registrant's `PublicKey`, expiring each entry after the requested
+/// time-to-live, and hands back pages of results using an opaque `cookie` as a resume token.
+///
+/// This is modelled on libp2p's rendezvous protocol.
+#[derive(Default)]
+pub struct RendezvousServer {
+    namespaces: HashMap<String, HashMap<PublicKey, Registration>>,
+    next_seq: u64,
+}
+
+impl RendezvousServer {
+    /// Creates a new, empty rendezvous server.
+    pub fn new() -> Self {
+        RendezvousServer {
+            namespaces: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Stores `info` under `namespace`, keyed by its public key, expiring after `ttl_secs`.
+    /// Re-registering the same key refreshes the entry and gives it a fresh sequence number so it
+    /// resurfaces to clients polling for new peers.
+    pub fn register(&mut self, namespace: String, info: TheirContactInfo, ttl_secs: u64) {
+        let expires_at = Instant::now() + Duration::from_secs(ttl_secs);
+        // Sequence numbers start at 1 so they never equal the `0` cursor handed out before any
+        // registration was seen.
+        self.next_seq += 1;
+        let seq = self.next_seq;
+        let key = info.pub_key;
+        let _ = self.namespaces
+            .entry(namespace)
+            .or_insert_with(HashMap::new)
+            .insert(key, Registration {
+                info: info,
+                expires_at: expires_at,
+                seq: seq,
+            });
+    }
+
+    /// Returns up to `limit` registrations for `namespace` whose sequence number is newer than the
+    /// one encoded in `cookie`. The returned cookie carries the highest sequence number seen, so
+    /// passing it back fetches the next page and, on a later poll, any peers registered since.
+    pub fn discover(&mut self,
+                    namespace: &str,
+                    limit: usize,
+                    cookie: Option<Vec<u8>>)
+                    -> (Vec<TheirContactInfo>, Vec<u8>) {
+        self.expire(namespace);
+
+        let after_seq = cookie.and_then(|c| Cursor::decode(&c)).map(|c| c.after_seq);
+
+        let registrations = match self.namespaces.get(namespace) {
+            Some(map) => map,
+            None => return (Vec::new(), Cursor { after_seq: after_seq.unwrap_or(0) }.encode()),
+        };
+
+        // Order by sequence number so paging is stable and monotonic: entries registered after the
+        // client's cookie always sort after it, and never shift earlier pages.
+        let mut fresh: Vec<&Registration> = registrations.values()
+            .filter(|r| after_seq.map_or(true, |s| r.seq > s))
+            .collect();
+        fresh.sort_by_key(|r| r.seq);
+
+        let page: Vec<&Registration> = fresh.into_iter().take(limit).collect();
+        let next_seq = page.last().map(|r| r.seq).or(after_seq).unwrap_or(0);
+        let infos = page.iter().map(|r| r.info.clone()).collect();
+
+        (infos, Cursor { after_seq: next_seq }.encode())
+    }
+
+    /// Drops every registration in `namespace` whose time-to-live has elapsed.
+    fn expire(&mut self, namespace: &str) {
+        let now = Instant::now();
+        if let Some(map) = self.namespaces.get_mut(namespace) {
+            let stale: Vec<PublicKey> = map.iter()
+                .filter(|&(_, r)| r.expires_at <= now)
+                .map(|(k, _)| *k)
+                .collect();
+            for key in stale {
+                let _ = map.remove(&key);
+            }
+        }
+    }
+}
+
+/// Shared rendezvous-server state, so the per-connection `RendezvousServerConnection` states can
+/// all register into and discover from the one namespace map on the event-loop thread.
+pub type SharedRendezvousServer = Rc<RefCell<RendezvousServer>>;
+
+/// Server side of the rendezvous protocol: one per accepted connection.
+///
+/// Reads `Message::Register` / `Message::Discover` requests off an established `Socket`, applies
+/// them to the shared `RendezvousServer`, and writes the matching `Message::Registered` /
+/// `Message::DiscoverResponse` back. The connection is kept open after each reply so a client can
+/// poll for newly-registered peers, mirroring the request/response shape of the client `Rendezvous`
+/// but driven from the receiving end.
+pub struct RendezvousServerConnection {
+    token: Token,
+    context: Context,
+    socket: Option<Socket>,
+    server: SharedRendezvousServer,
+    msg: Option<(Message, Priority)>,
+}
+
+impl RendezvousServerConnection {
+    /// Takes over an accepted `socket`, serving rendezvous requests against `server` until the
+    /// peer disconnects or errors.
+    pub fn start(core: &mut Core,
+                 el: &mut EventLoop<Core>,
+                 token: Token,
+                 socket: Socket,
+                 server: SharedRendezvousServer)
+                 -> ::Res<Context> {
+        try!(el.reregister(&socket,
+                           token,
+                           EventSet::readable() | EventSet::error() | EventSet::hup(),
+                           PollOpt::edge()));
+
+        let context = core.get_new_context();
+        let state = Rc::new(RefCell::new(RendezvousServerConnection {
+            token: token,
+            context: context,
+            socket: Some(socket),
+            server: server,
+            msg: None,
+        }));
+
+        let _ = core.insert_context(token, context);
+        let _ = core.insert_state(context, state);
+
+        Ok(context)
+    }
+
+    fn read(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        match self.socket.as_mut().unwrap().read::<Message>() {
+            Ok(Some(Message::Register { namespace, info, ttl_secs })) => {
+                self.server.borrow_mut().register(namespace, info, ttl_secs);
+                self.reply(core, el, Message::Registered);
+            }
+            Ok(Some(Message::Discover { namespace, limit, cookie })) => {
+                let (registrations, cookie) =
+                    self.server.borrow_mut().discover(&namespace, limit, cookie);
+                self.reply(core,
+                           el,
+                           Message::DiscoverResponse {
+                               registrations: registrations,
+                               cookie: cookie,
+                           });
+            }
+            Ok(Some(_)) | Err(_) => self.terminate(core, el),
+            Ok(None) => (),
+        }
+    }
+
+    /// Queues `msg` for writing and switches the socket to writable.
+    fn reply(&mut self, core: &mut Core, el: &mut EventLoop<Core>, msg: Message) {
+        self.msg = Some((msg, RENDEZVOUS_MSG_PRIORITY));
+        if el.reregister(self.socket.as_ref().unwrap(),
+                         self.token,
+                         EventSet::readable() | EventSet::writable() | EventSet::error() |
+                         EventSet::hup(),
+                         PollOpt::edge())
+            .is_err() {
+            self.terminate(core, el);
+        }
+    }
+
+    fn write(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let msg = self.msg.take();
+        match self.socket.as_mut().unwrap().write(el, self.token, msg) {
+            // Fully flushed: go back to waiting for the next request so the client can keep polling.
+            Ok(true) => {
+                let _ = el.reregister(self.socket.as_ref().unwrap(),
+                                      self.token,
+                                      EventSet::readable() | EventSet::error() | EventSet::hup(),
+                                      PollOpt::edge());
+            }
+            Ok(false) => (),
+            Err(_) => self.terminate(core, el),
+        }
+    }
+}
+
+impl State for RendezvousServerConnection {
+    fn ready(&mut self,
+             core: &mut Core,
+             el: &mut EventLoop<Core>,
+             _token: Token,
+             event_set: EventSet) {
+        if event_set.is_error() || event_set.is_hup() {
+            return self.terminate(core, el);
+        }
+        if event_set.is_writable() && self.msg.is_some() {
+            self.write(core, el);
+        }
+        if event_set.is_readable() {
+            self.read(core, el);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let _ = core.remove_context(self.token);
+        let _ = core.remove_state(self.context);
+        if let Some(socket) = self.socket.take() {
+            let _ = el.deregister(&socket);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Opaque paging token handed back to discovery clients. Kept private so its layout can change
+/// without affecting the wire format, which only ever sees the encoded bytes.
+#[derive(Clone, Copy, RustcEncodable, RustcDecodable)]
+struct Cursor {
+    after_seq: u64,
+}
+
+impl Cursor {
+    fn encode(&self) -> Vec<u8> {
+        serialise(self).unwrap_or_else(|_| Vec::new())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Cursor> {
+        ::maidsafe_utilities::serialisation::deserialise(bytes).ok()
+    }
+}
+
+/// Outcome of driving a `Rendezvous` client state machine to completion.
+pub enum RendezvousResult {
+    /// Our registration was accepted by the server.
+    Registered,
+    /// The server returned a page of peers together with the resume cookie.
+    Discovered(Vec<TheirContactInfo>, Vec<u8>),
+}
+
+pub type Finish = Box<FnMut(&mut Core, &mut EventLoop<Core>, Context, Option<RendezvousResult>)>;
+
+/// Client side of the rendezvous protocol.
+///
+/// Writes a single `Register` or `Discover` request over an established `Socket` and waits for the
+/// server's reply, which `result_to_event` turns into `Event::Registered` /
+/// `Event::DiscoveredPeers`. The shape mirrors `ConnectionCandidate`: one request out, one
+/// response in, then pass the outcome to `finish`. The socket is not handed back.
+pub struct Rendezvous {
+    token: Token,
+    context: Context,
+    socket: Option<Socket>,
+    msg: Option<(Message, Priority)>,
+    finish: Finish,
+}
+
+impl Rendezvous {
+    /// Registers `info` under `namespace` with the server on the far end of `socket`.
+    pub fn register(core: &mut Core,
+                    el: &mut EventLoop<Core>,
+                    token: Token,
+                    socket: Socket,
+                    namespace: String,
+                    info: TheirContactInfo,
+                    ttl_secs: u64,
+                    finish: Finish)
+                    -> ::Res<Context> {
+        let msg = Message::Register {
+            namespace: namespace,
+            info: info,
+            ttl_secs: ttl_secs,
+        };
+        Self::start(core, el, token, socket, msg, finish)
+    }
+
+    /// Asks the server on the far end of `socket` for up to `limit` peers in `namespace`, resuming
+    /// after `cookie` if supplied.
+    pub fn discover(core: &mut Core,
+                    el: &mut EventLoop<Core>,
+                    token: Token,
+                    socket: Socket,
+                    namespace: String,
+                    limit: usize,
+                    cookie: Option<Vec<u8>>,
+                    finish: Finish)
+                    -> ::Res<Context> {
+        let msg = Message::Discover {
+            namespace: namespace,
+            limit: limit,
+            cookie: cookie,
+        };
+        Self::start(core, el, token, socket, msg, finish)
+    }
+
+    fn start(core: &mut Core,
+             el: &mut EventLoop<Core>,
+             token: Token,
+             socket: Socket,
+             msg: Message,
+             finish: Finish)
+             -> ::Res<Context> {
+        try!(el.reregister(&socket,
+                           token,
+                           EventSet::readable() | EventSet::writable() | EventSet::error() |
+                           EventSet::hup(),
+                           PollOpt::edge()));
+
+        let context = core.get_new_context();
+        let state = Rc::new(RefCell::new(Rendezvous {
+            token: token,
+            context: context,
+            socket: Some(socket),
+            msg: Some((msg, RENDEZVOUS_MSG_PRIORITY)),
+            finish: finish,
+        }));
+
+        let _ = core.insert_context(token, context);
+        let _ = core.insert_state(context, state);
+
+        Ok(context)
+    }
+
+    fn read(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        match self.socket.as_mut().unwrap().read::<Message>() {
+            Ok(Some(Message::Registered)) => self.done(core, el, RendezvousResult::Registered),
+            Ok(Some(Message::DiscoverResponse { registrations, cookie })) => {
+                self.done(core, el, RendezvousResult::Discovered(registrations, cookie))
+            }
+            Ok(Some(_)) | Err(_) => self.handle_error(core, el),
+            Ok(None) => (),
+        }
+    }
+
+    fn write(&mut self,
+             core: &mut Core,
+             el: &mut EventLoop<Core>,
+             msg: Option<(Message, Priority)>) {
+        match self.socket.as_mut().unwrap().write(el, self.token, msg) {
+            Ok(_) => (),
+            Err(_) => self.handle_error(core, el),
+        }
+    }
+
+    fn done(&mut self,
+            core: &mut Core,
+            el: &mut EventLoop<Core>,
+            result: RendezvousResult) {
+        let _ = core.remove_context(self.token);
+        let _ = core.remove_state(self.context);
+        let context = self.context;
+        (*self.finish)(core, el, context, Some(result));
+    }
+
+    fn handle_error(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        self.terminate(core, el);
+        let context = self.context;
+        (*self.finish)(core, el, context, None);
+    }
+}
+
+impl State for Rendezvous {
+    fn ready(&mut self,
+             core: &mut Core,
+             el: &mut EventLoop<Core>,
+             _token: Token,
+             event_set: EventSet) {
+        if event_set.is_error() || event_set.is_hup() {
+            return self.handle_error(core, el);
+        }
+        if event_set.is_writable() {
+            let msg = self.msg.take();
+            if msg.is_some() {
+                self.write(core, el, msg);
+            }
+        }
+        if event_set.is_readable() {
+            self.read(core, el);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, el: &mut EventLoop<Core>) {
+        let _ = core.remove_context(self.token);
+        let _ = core.remove_state(self.context);
+        if let Some(socket) = self.socket.take() {
+            let _ = el.deregister(&socket);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Translates a completed client outcome into the user-facing `Event`.
+pub fn result_to_event(result: RendezvousResult) -> Event {
+    match result {
+        RendezvousResult::Registered => Event::Registered,
+        RendezvousResult::Discovered(infos, _cookie) => Event::DiscoveredPeers(infos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::sign;
+
+    use event::TheirContactInfo;
+
+    fn contact_info() -> TheirContactInfo {
+        TheirContactInfo {
+            secret: None,
+            static_addrs: vec![],
+            rendezvous_addrs: vec![],
+            external_addrs: vec![],
+            pub_key: sign::gen_keypair().0,
+        }
+    }
+
+    #[test]
+    fn discover_pages_by_sequence_cursor() {
+        let mut server = RendezvousServer::new();
+        let a = contact_info();
+        let b = contact_info();
+        let c = contact_info();
+        server.register("space".to_owned(), a.clone(), 60);
+        server.register("space".to_owned(), b.clone(), 60);
+        server.register("space".to_owned(), c.clone(), 60);
+
+        let (first, cookie) = server.discover("space", 2, None);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].pub_key, a.pub_key);
+        assert_eq!(first[1].pub_key, b.pub_key);
+
+        let (second, cookie) = server.discover("space", 2, Some(cookie));
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].pub_key, c.pub_key);
+
+        // A fully-drained cursor returns nothing until a new peer registers.
+        let (empty, cookie) = server.discover("space", 2, Some(cookie));
+        assert!(empty.is_empty());
+
+        let d = contact_info();
+        server.register("space".to_owned(), d.clone(), 60);
+        let (fresh, _) = server.discover("space", 2, Some(cookie));
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(fresh[0].pub_key, d.pub_key);
+    }
+
+    #[test]
+    fn unknown_namespace_discovers_nothing() {
+        let mut server = RendezvousServer::new();
+        let (infos, _) = server.discover("absent", 10, None);
+        assert!(infos.is_empty());
+    }
+
+    #[test]
+    fn registrations_expire_after_their_ttl() {
+        let mut server = RendezvousServer::new();
+        server.register("space".to_owned(), contact_info(), 0);
+        // A zero-second TTL is already in the past by the time discovery runs `expire`.
+        let (infos, _) = server.discover("space", 10, None);
+        assert!(infos.is_empty());
+    }
+
+    #[test]
+    fn re_registering_refreshes_the_sequence() {
+        let mut server = RendezvousServer::new();
+        let peer = contact_info();
+        server.register("space".to_owned(), peer.clone(), 60);
+        let (_, cookie) = server.discover("space", 10, None);
+
+        // After the client has drained the cursor, the same peer re-registering resurfaces it.
+        server.register("space".to_owned(), peer.clone(), 60);
+        let (fresh, _) = server.discover("space", 10, Some(cookie));
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(fresh[0].pub_key, peer.pub_key);
+    }
+}